@@ -1,16 +1,43 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use super::{style::Style, ColorOpacity};
 
 type StyleId = u64;
 type ColorId = u64;
 
+/// Highlight ids from neovim are small, dense integers assigned sequentially by its
+/// highlight table. Treat anything implausibly larger than that as malformed RPC input
+/// and drop the update instead of resizing `defined_styles`/`style_generations` to match
+/// an unvalidated, attacker/bug-controlled `u64` id, which could panic with a capacity
+/// overflow or exhaust memory.
+const MAX_STYLE_ID: StyleId = 1 << 20;
+
+/// A normalized, hashable snapshot of a `Style`'s contents (fg, bg, opacity and
+/// attributes, with opacity already applied), used to detect that two style ids resolve
+/// to byte-identical styles so they can share one `Arc` instead of each getting its own
+/// allocation. `Style` itself can't derive `Hash`/`Eq` directly since opacity is stored as
+/// a float, so we key on its `Debug` output, which covers every field it has.
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct StyleKey(String);
+
+impl StyleKey {
+    fn new(style: &Style) -> Self {
+        Self(format!("{style:?}"))
+    }
+}
+
 /// The `StyleRegistry` struct is responsible for keeping styles updated with corresponding opacity settings.
 /// Styles and opacities are associated with background and foreground colors.
 #[derive(Default)]
 pub struct StyleRegistry {
-    /// Maps style IDs (neovim highlight table id) to their corresponding styles
-    defined_styles: HashMap<StyleId, Arc<Style>>,
+    /// Maps style IDs (neovim highlight table id) to their corresponding styles.
+    /// Neovim assigns these ids as small, dense, sequentially-increasing integers, so a
+    /// `Vec` indexed directly by id avoids hashing on the per-glyph style lookup done
+    /// during grid rendering. Slots for ids that have never been set are `None`.
+    defined_styles: Vec<Option<Arc<Style>>>,
 
     /// Associates each color with opacity settings.
     /// This is used to update the opacity of all styles when the global opacity changes.
@@ -21,30 +48,225 @@ pub struct StyleRegistry {
 
     /// Maps foreground colors to their corresponding style IDs
     foreground_color_style_map: HashMap<ColorId, Vec<StyleId>>,
+
+    /// Bumped on every mutation of `defined_styles`, so callers that cache paint state
+    /// derived from styles (e.g. the grid/cursor renderer) can cheaply tell whether
+    /// anything has changed since the last frame instead of rebuilding unconditionally.
+    generation: u64,
+
+    /// Per-style generation, indexed like `defined_styles`. Bumped only for the ids that
+    /// actually changed, allowing partial invalidation when `set_opacity` only touches a
+    /// subset of styles. `None` for ids that have never been set, mirroring the
+    /// `Option`-for-sparse-ids convention `defined_styles`/`get_style` use.
+    style_generations: Vec<Option<u64>>,
+
+    /// Interns styles by content so that highlight ids resolving to byte-identical styles
+    /// share a single `Arc`. This lets the renderer batch runs of glyphs by `Arc::ptr_eq`
+    /// instead of deep-comparing `Style` values.
+    interned_styles: HashMap<StyleKey, Arc<Style>>,
+
+    /// Maps highlight-group names to their current style id, fed from `hl_group_set` events.
+    group_style_ids: HashMap<String, StyleId>,
+
+    /// Reverse of `group_style_ids`, so a style id can be resolved back to the group
+    /// name(s) that currently own it while applying opacity. A set rather than a single
+    /// name because neovim can assign the same attr id to more than one highlight group
+    /// (e.g. `:hi link Foo Bar`). Kept in sync by `register_group_name`.
+    style_group_names: HashMap<StyleId, HashSet<String>>,
+
+    /// Opacity overrides set by highlight-group name via `set_group_opacity`, keyed by name
+    /// (not style id) so the override survives a group being reassigned to a new id by a
+    /// colorscheme reload or `:hi` redefinition. These take precedence over the color-level
+    /// opacity applied via `set_opacity` and `update_all_styles_opacity`.
+    group_opacities: HashMap<String, ColorOpacity>,
+
+    /// The most recently seen global default opacity, so `set_group_opacity` can reapply
+    /// an override immediately instead of waiting for the next color-opacity pass.
+    default_opacity: f32,
 }
 
 impl StyleRegistry {
     pub fn new() -> Self {
         Self {
             defined_opacities: HashMap::new(),
-            defined_styles: HashMap::new(),
+            defined_styles: Vec::new(),
             background_color_style_map: HashMap::new(),
             foreground_color_style_map: HashMap::new(),
+            generation: 0,
+            style_generations: Vec::new(),
+            interned_styles: HashMap::new(),
+            group_style_ids: HashMap::new(),
+            style_group_names: HashMap::new(),
+            group_opacities: HashMap::new(),
+            default_opacity: 1.0,
+        }
+    }
+
+    /// Associates a highlight-group name with the style id it currently resolves to.
+    /// Should be called whenever neovim emits an `hl_group_set` event.
+    ///
+    /// If the name was previously bound to a different id (a colorscheme reload or `:hi`
+    /// redefinition reassigning the group's attr id), the stale reverse mapping is removed
+    /// so any override for this name stops applying to whatever now owns the old id. Other
+    /// names still mapped to that old id (neovim can assign the same id to multiple groups)
+    /// are left untouched.
+    pub fn register_group_name(&mut self, name: String, id: StyleId) {
+        if let Some(old_id) = self.group_style_ids.get(&name).copied() {
+            if old_id != id {
+                if let Some(names) = self.style_group_names.get_mut(&old_id) {
+                    names.remove(&name);
+                    if names.is_empty() {
+                        self.style_group_names.remove(&old_id);
+                    }
+                }
+            }
+        }
+
+        self.style_group_names.entry(id).or_default().insert(name.clone());
+        self.group_style_ids.insert(name.clone(), id);
+
+        self.reapply_group_opacity(&name, id);
+    }
+
+    /// Overrides the opacity of a single highlight group by name, independent of its colors.
+    /// The override is kept by name even if the group's id isn't known yet (config applied
+    /// before the first `hl_group_set`), and is applied immediately if the group's style is
+    /// already defined; otherwise it is picked up once the name and style are registered.
+    pub fn set_group_opacity(&mut self, name: &str, opacity: ColorOpacity) {
+        self.group_opacities.insert(name.to_owned(), opacity);
+
+        if let Some(&id) = self.group_style_ids.get(name) {
+            self.reapply_group_opacity(name, id);
+        }
+    }
+
+    /// Re-applies a pending group opacity override to the style currently stored at `id`,
+    /// if both the override and the style exist yet.
+    fn reapply_group_opacity(&mut self, name: &str, id: StyleId) {
+        if !self.group_opacities.contains_key(name) {
+            return;
+        }
+
+        if let Some(arc) = self.get_style(id) {
+            let mut style = (**arc).to_owned();
+            self.apply_group_opacity_override(&mut style, id, self.default_opacity);
+            self.insert_style(id, style);
+        }
+    }
+
+    /// Applies a highlight-group-name opacity override for `id`, if one has been registered
+    /// for any of the names currently bound to it. Resolved after any color-level opacity,
+    /// so a group override always wins.
+    fn apply_group_opacity_override(&self, style: &mut Style, id: StyleId, default_opacity: f32) {
+        let opacity = self.style_group_names.get(&id).and_then(|names| {
+            names
+                .iter()
+                .find_map(|name| self.group_opacities.get(name))
+        });
+
+        if let Some(opacity) = opacity {
+            style.set_background_opacity(opacity, default_opacity);
+            style.set_foreground_opacity(opacity, default_opacity);
         }
     }
 
+    /// The current global generation. Bumped each time a style is stored (by `set_style`,
+    /// an opacity pass or a group override), so a caller that cached this value can tell
+    /// whether it needs to rebuild anything derived from the current styles. Changes that
+    /// store no style (e.g. a color opacity no defined style uses) leave it unchanged.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// The generation at which the style for `id` was last changed, if it has ever been set.
+    pub fn style_generation(&self, id: StyleId) -> Option<u64> {
+        self.style_generations.get(id as usize).copied().flatten()
+    }
+
+    fn bump_generation(&mut self, id: StyleId) {
+        if id > MAX_STYLE_ID {
+            return;
+        }
+
+        self.generation += 1;
+
+        let index = id as usize;
+        if index >= self.style_generations.len() {
+            self.style_generations.resize(index + 1, None);
+        }
+        self.style_generations[index] = Some(self.generation);
+    }
+
     pub fn default_style(&self) -> Option<Style> {
-        self.defined_styles.get(&0).map(|style| (**style).clone())
+        self.defined_styles
+            .first()
+            .and_then(Option::as_ref)
+            .map(|style| (**style).clone())
+    }
+
+    /// Iterates over the populated style slots, skipping ids that have never been set.
+    pub fn defined_styles(&self) -> impl Iterator<Item = &Arc<Style>> {
+        self.defined_styles.iter().filter_map(Option::as_ref)
+    }
+
+    fn get_style(&self, id: StyleId) -> Option<&Arc<Style>> {
+        self.defined_styles.get(id as usize).and_then(Option::as_ref)
     }
 
-    pub fn defined_styles(&self) -> &HashMap<u64, Arc<Style>> {
-        &self.defined_styles
+    /// Looks up the interned `Arc` for a style with identical contents, reusing it instead
+    /// of allocating a new one. `set_opacity`/`update_all_styles_opacity` change a style's
+    /// contents by storing a changed copy, so this must be re-run (and the old key
+    /// re-checked for eviction) any time a style changes, not just when it's first defined.
+    fn intern(&mut self, style: Style) -> Arc<Style> {
+        let key = StyleKey::new(&style);
+        self.interned_styles
+            .entry(key)
+            .or_insert_with(|| Arc::new(style))
+            .clone()
+    }
+
+    /// Removes an interned entry once nothing in `defined_styles` still references it
+    /// (i.e. only this function's local `Arc` and the intern map hold it).
+    fn evict_if_unused(&mut self, style: Option<Arc<Style>>) {
+        if let Some(style) = style {
+            if Arc::strong_count(&style) <= 2 {
+                self.interned_styles.remove(&StyleKey::new(&style));
+            }
+        }
+    }
+
+    fn insert_style(&mut self, id: StyleId, style: Style) {
+        if id > MAX_STYLE_ID {
+            return;
+        }
+
+        let arc = self.intern(style);
+
+        let index = id as usize;
+        if index >= self.defined_styles.len() {
+            self.defined_styles.resize(index + 1, None);
+        }
+        let previous = self.defined_styles[index].replace(arc);
+        self.evict_if_unused(previous);
+
+        self.bump_generation(id);
     }
 
     pub fn set_style(&mut self, mut style: Style, id: u64, default_opacity: f32) {
+        // Reject an implausible id up front, before any bookkeeping runs. In particular
+        // `update_color_to_style_mapping` below pushes into unbounded `Vec<StyleId>`
+        // buckets keyed by color, so letting it run for a rejected id would leak an entry
+        // on every call — exactly the memory-exhaustion case `insert_style`'s own
+        // `MAX_STYLE_ID` guard is meant to close.
+        if id > MAX_STYLE_ID {
+            return;
+        }
+
+        self.default_opacity = default_opacity;
         self.update_style_opacities_from_existing_mapping(&mut style, default_opacity);
+        self.apply_group_opacity_override(&mut style, id, default_opacity);
         self.update_color_to_style_mapping(&style, id);
-        self.defined_styles.insert(id, Arc::new(style));
+        self.insert_style(id, style);
     }
 
     /// Set the foreground and background opacity of a color and update all styles that use this color
@@ -54,16 +276,19 @@ impl StyleRegistry {
         color_opacity: ColorOpacity,
         default_opacity: f32,
     ) {
+        self.default_opacity = default_opacity;
+
         // Update the opacity of all styles that use this color
         let mut update_opacity =
             |styles_map: &HashMap<ColorId, Vec<StyleId>>,
              set_opacity_fn: fn(&mut Style, &ColorOpacity, f32)| {
                 if let Some(styles_id) = styles_map.get(&color) {
                     styles_id.iter().for_each(|id| {
-                        if let Some(arc) = self.defined_styles.get(id) {
+                        if let Some(arc) = self.get_style(*id) {
                             let mut style = (**arc).to_owned();
                             set_opacity_fn(&mut style, &color_opacity, default_opacity);
-                            self.defined_styles.insert(*id, Arc::new(style));
+                            self.apply_group_opacity_override(&mut style, *id, default_opacity);
+                            self.insert_style(*id, style);
                         }
                     });
                 }
@@ -83,6 +308,8 @@ impl StyleRegistry {
 
     /// Update all styles with existing color opacity settings with updated global opacity
     pub fn update_all_styles_opacity(&mut self, default_opacity: f32) {
+        self.default_opacity = default_opacity;
+
         let get_updated_styles =
             |color_style_map: &HashMap<ColorId, Vec<StyleId>>,
              set_opacity_fn: fn(&mut Style, &ColorOpacity, f32)| {
@@ -92,11 +319,12 @@ impl StyleRegistry {
                         self.defined_opacities.get(color).map(|color_opacity| {
                             style_ids
                                 .iter()
-                                .filter_map(|id| self.defined_styles.get_key_value(id))
+                                .filter_map(|id| self.get_style(*id).map(|arc| (*id, arc)))
                                 .map(|(id, arc)| {
                                     let mut style = (**arc).to_owned();
                                     set_opacity_fn(&mut style, color_opacity, default_opacity);
-                                    (*id, style)
+                                    self.apply_group_opacity_override(&mut style, id, default_opacity);
+                                    (id, style)
                                 })
                         })
                     })
@@ -114,12 +342,12 @@ impl StyleRegistry {
             Style::set_foreground_opacity,
         );
 
-        new_background_styles.iter().for_each(|(id, style)| {
-            self.defined_styles.insert(*id, Arc::new(style.clone()));
+        new_background_styles.into_iter().for_each(|(id, style)| {
+            self.insert_style(id, style);
         });
 
-        new_foreground_styles.iter().for_each(|(id, style)| {
-            self.defined_styles.insert(*id, Arc::new(style.clone()));
+        new_foreground_styles.into_iter().for_each(|(id, style)| {
+            self.insert_style(id, style);
         });
     }
 
@@ -153,3 +381,107 @@ impl StyleRegistry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_style_returns_none_for_ids_that_were_never_set() {
+        let mut registry = StyleRegistry::new();
+        registry.set_style(Style::default(), 5, 1.0);
+
+        assert!(registry.get_style(5).is_some());
+        assert!(registry.get_style(2).is_none());
+        assert!(registry.get_style(0).is_none());
+    }
+
+    #[test]
+    fn implausibly_large_style_ids_are_dropped_instead_of_resizing() {
+        let mut registry = StyleRegistry::new();
+        registry.set_style(Style::default(), MAX_STYLE_ID + 1, 1.0);
+
+        assert_eq!(registry.generation(), 0);
+        assert!(registry.get_style(MAX_STYLE_ID + 1).is_none());
+        assert!(registry.background_color_style_map.is_empty());
+        assert!(registry.foreground_color_style_map.is_empty());
+    }
+
+    #[test]
+    fn style_generation_is_none_for_ids_that_were_never_set() {
+        let mut registry = StyleRegistry::new();
+        registry.set_style(Style::default(), 5, 1.0);
+
+        assert!(registry.style_generation(5).is_some());
+        assert_eq!(registry.style_generation(2), None);
+    }
+
+    #[test]
+    fn generation_is_bumped_on_every_mutation() {
+        let mut registry = StyleRegistry::new();
+        assert_eq!(registry.generation(), 0);
+
+        registry.set_style(Style::default(), 0, 1.0);
+        assert_eq!(registry.generation(), 1);
+
+        registry.set_style(Style::default(), 1, 1.0);
+        assert_eq!(registry.generation(), 2);
+    }
+
+    #[test]
+    fn identical_styles_are_interned_into_one_arc() {
+        let mut registry = StyleRegistry::new();
+        registry.set_style(Style::default(), 1, 1.0);
+        registry.set_style(Style::default(), 2, 1.0);
+
+        let first = registry.get_style(1).unwrap().clone();
+        let second = registry.get_style(2).unwrap().clone();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn set_group_opacity_reapplies_to_an_already_defined_style() {
+        let mut registry = StyleRegistry::new();
+        registry.register_group_name("Pmenu".to_owned(), 1);
+        registry.set_style(Style::default(), 1, 1.0);
+        let generation_before = registry.generation();
+
+        registry.set_group_opacity("Pmenu", ColorOpacity::default());
+
+        // The style stored at the group's id must have been re-inserted immediately
+        // (not just recorded for the next `set_style`/`set_opacity` pass), so a caller
+        // watching `generation` sees that something changed right away.
+        assert!(registry.generation() > generation_before);
+    }
+
+    #[test]
+    fn group_opacity_override_does_not_apply_to_the_groups_old_id_after_it_moves() {
+        let mut registry = StyleRegistry::new();
+        registry.register_group_name("Pmenu".to_owned(), 1);
+        registry.set_group_opacity("Pmenu", ColorOpacity::default());
+
+        registry.register_group_name("Pmenu".to_owned(), 2);
+
+        let names_for_old_id = registry.style_group_names.get(&1);
+        assert!(names_for_old_id.map_or(true, |names| !names.contains("Pmenu")));
+
+        let names_for_new_id = registry.style_group_names.get(&2).unwrap();
+        assert!(names_for_new_id.contains("Pmenu"));
+    }
+
+    #[test]
+    fn moving_one_of_two_group_names_sharing_a_style_id_keeps_the_others_mapping() {
+        let mut registry = StyleRegistry::new();
+        registry.register_group_name("Foo".to_owned(), 1);
+        registry.register_group_name("Bar".to_owned(), 1);
+        registry.set_group_opacity("Foo", ColorOpacity::default());
+
+        // Reassigning "Bar" elsewhere must not evict "Foo"'s reverse mapping for id 1, even
+        // though both names previously shared that id.
+        registry.register_group_name("Bar".to_owned(), 2);
+
+        let names_for_id_1 = registry.style_group_names.get(&1).unwrap();
+        assert!(names_for_id_1.contains("Foo"));
+        assert!(!names_for_id_1.contains("Bar"));
+    }
+}